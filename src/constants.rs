@@ -0,0 +1,64 @@
+// IceBox
+// Written in 2017 by
+//   Andrew Poelstra <icebox@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Constants
+//!
+//! APDU-level constants used to construct and parse messages to/from the dongle
+//!
+
+/// Constants for the Ledger/btchip APDU protocol
+pub mod apdu {
+    /// Constants specific to the btchip (Ledger Bitcoin app) protocol
+    pub mod ledger {
+        /// The CLA byte used by every btchip APDU
+        pub const BTCHIP_CLA: u8 = 0xe0;
+
+        /// Instruction bytes (the INS field of the APDU header)
+        pub mod ins {
+            /// GET FIRMWARE VERSION
+            pub const GET_FIRMWARE_VERSION: u8 = 0xc4;
+            /// GET RANDOM
+            pub const GET_RANDOM: u8 = 0xc0;
+            /// GET WALLET PUBLIC KEY
+            pub const GET_WALLET_PUBLIC_KEY: u8 = 0x40;
+            /// GET TRUSTED INPUT
+            pub const GET_TRUSTED_INPUT: u8 = 0x42;
+            /// UNTRUSTED HASH TRANSACTION INPUT START
+            pub const UNTRUSTED_HASH_TRANSACTION_INPUT_START: u8 = 0x44;
+            /// UNTRUSTED HASH SIGN
+            pub const UNTRUSTED_HASH_SIGN: u8 = 0x48;
+            /// UNTRUSTED HASH TRANSACTION INPUT FINALIZE FULL
+            pub const UNTRUSTED_HASH_TRANSACTION_INPUT_FINALIZE_FULL: u8 = 0x4a;
+            /// SIGN MESSAGE (used for both the PREPARE and SIGN steps, which
+            /// are distinguished by P1)
+            pub const SIGN_MESSAGE: u8 = 0x4e;
+        }
+
+        /// Status words (the SW1/SW2 trailer of an APDU reply)
+        pub mod sw {
+            /// Success
+            pub const OK: u16 = 0x9000;
+            /// Security status not satisfied; the device is PIN-locked
+            pub const SECURITY_STATUS_NOT_SATISFIED: u16 = 0x6982;
+            /// The user declined to confirm the requested action on-device
+            pub const USER_CANCELLED: u16 = 0x6985;
+            /// The request contained invalid data
+            pub const BAD_DATA: u16 = 0x6a80;
+            /// The requested instruction is not supported by the running app
+            pub const INSTRUCTION_NOT_SUPPORTED: u16 = 0x6d00;
+            /// The device is halted and must be unplugged and replugged
+            pub const HALTED: u16 = 0x6faa;
+        }
+    }
+}
@@ -19,7 +19,7 @@
 //!
 
 use bitcoin::blockdata::transaction::Transaction;
-use byteorder::{WriteBytesExt, BigEndian};
+use byteorder::{WriteBytesExt, ReadBytesExt, BigEndian, LittleEndian};
 use secp256k1::{Secp256k1, ContextFlag};
 use secp256k1::key::PublicKey;
 use std::cmp;
@@ -47,6 +47,56 @@ pub trait Command {
     fn into_reply(self) -> (u16, Vec<u8>);
 }
 
+/// A decoded APDU status word, as returned in the last two bytes of every
+/// dongle reply
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StatusWord {
+    /// 0x9000: success
+    Ok,
+    /// 0x6982: security status not satisfied; the device is PIN-locked
+    SecurityStatusNotSatisfied,
+    /// 0x6985: the user declined to confirm the requested action on-device
+    UserCancelled,
+    /// 0x6a80: the request contained invalid data
+    BadData,
+    /// 0x6d00: the requested instruction is not supported by the running app
+    InstructionNotSupported,
+    /// 0x6faa: the device is halted and must be unplugged and replugged
+    Halted,
+    /// Any other status word, preserved verbatim
+    Unknown(u16)
+}
+
+impl StatusWord {
+    /// Decodes a raw sw1/sw2 pair into a `StatusWord`
+    pub fn from_u16(sw: u16) -> StatusWord {
+        match sw {
+            apdu::ledger::sw::OK => StatusWord::Ok,
+            apdu::ledger::sw::SECURITY_STATUS_NOT_SATISFIED => StatusWord::SecurityStatusNotSatisfied,
+            apdu::ledger::sw::USER_CANCELLED => StatusWord::UserCancelled,
+            apdu::ledger::sw::BAD_DATA => StatusWord::BadData,
+            apdu::ledger::sw::INSTRUCTION_NOT_SUPPORTED => StatusWord::InstructionNotSupported,
+            apdu::ledger::sw::HALTED => StatusWord::Halted,
+            other => StatusWord::Unknown(other)
+        }
+    }
+
+    /// Whether this status word indicates success
+    pub fn is_ok(&self) -> bool {
+        *self == StatusWord::Ok
+    }
+}
+
+/// Pops the trailing sw1/sw2 bytes off a reply and decodes them
+fn take_status_word(data: &mut Vec<u8>) -> Result<u16, Error> {
+    if data.len() < 2 {
+        return Err(Error::UnexpectedEof);
+    }
+    let sw2 = data.pop().unwrap();
+    let sw1 = data.pop().unwrap();
+    Ok(((sw1 as u16) << 8) + sw2 as u16)
+}
+
 /// GET FIRMWARE VERSION message
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct GetFirmwareVersion {
@@ -77,14 +127,14 @@ impl Command for GetFirmwareVersion {
     }
 
     fn decode_reply(&mut self, mut data: Vec<u8>) -> Result<(), Error> {
-        if data.len() < 2 {
-            return Err(Error::UnexpectedEof);
-        }
-        let sw2 = data.pop().unwrap();
-        let sw1 = data.pop().unwrap();
+        self.sw = try!(take_status_word(&mut data));
         self.reply = data;
-        self.sw = ((sw1 as u16) << 8) + sw2 as u16;
-        Ok(())
+        let status = StatusWord::from_u16(self.sw);
+        if status.is_ok() {
+            Ok(())
+        } else {
+            Err(Error::ApduBadStatus(status))
+        }
     }
 
     fn into_reply(self) -> (u16, Vec<u8>) {
@@ -93,7 +143,7 @@ impl Command for GetFirmwareVersion {
 }
 
 /// Response to the GET FIRMWARE VERSION message
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone)]
 pub struct FirmwareVersion {
     /// Whether or not the device uses compressed keys
     pub compressed: bool,
@@ -158,6 +208,89 @@ impl Response for FirmwareVersion {
     }
 }
 
+/// Firmware versions older than this are missing features this crate relies
+/// on (in particular, the wider trusted-input/signing APDU headers added
+/// alongside segwit support) and are rejected by [`FirmwareVersion::check_not_deprecated`]
+pub const DEPRECATE_VERSION_BEFORE: (u8, u8, u8) = (1, 4, 0);
+
+impl FirmwareVersion {
+    fn version_tuple(&self) -> (u8, u8, u8) {
+        (self.major_version, self.minor_version, self.patch_version)
+    }
+
+    /// Whether this firmware is new enough to sign segwit inputs
+    pub fn supports_segwit(&self) -> bool {
+        self.version_tuple() >= DEPRECATE_VERSION_BEFORE
+    }
+
+    /// The combined length, in bytes, of the fixed (non-blob, non-script)
+    /// fields in a per-input record of an UNTRUSTED HASH TRANSACTION INPUT
+    /// START payload: the marker byte, the trusted-input length prefix (always
+    /// one byte, since the blob itself is a fixed size), the subscript length
+    /// prefix, and the 4-byte sequence number. Firmware before 1.4.0 expects a
+    /// single-byte subscript length prefix (for a total of 7 bytes); current
+    /// firmware expects a two-byte prefix (8 bytes).
+    pub fn trusted_input_header_len(&self) -> usize {
+        if self.supports_segwit() { 8 } else { 7 }
+    }
+
+    /// Returns `Error::FirmwareTooOld` if this version predates the minimum
+    /// firmware this crate supports.
+    pub fn check_not_deprecated(&self) -> Result<(), Error> {
+        if self.version_tuple() < DEPRECATE_VERSION_BEFORE {
+            Err(Error::FirmwareTooOld)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+// Manual, rather than derived, so that equality and ordering both reduce to
+// the (major, minor, patch) tuple; deriving `PartialEq`/`Eq` over every field
+// while comparing only the version numbers in `Ord` would make two versions
+// that differ only in loader/architecture fields compare `Equal` yet `!=`.
+impl PartialEq for FirmwareVersion {
+    fn eq(&self, other: &FirmwareVersion) -> bool {
+        self.version_tuple() == other.version_tuple()
+    }
+}
+
+impl Eq for FirmwareVersion {}
+
+impl PartialOrd for FirmwareVersion {
+    fn partial_cmp(&self, other: &FirmwareVersion) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for FirmwareVersion {
+    fn cmp(&self, other: &FirmwareVersion) -> cmp::Ordering {
+        self.version_tuple().cmp(&other.version_tuple())
+    }
+}
+
+/// The address encoding to request from the device in a GET WALLET PUBLIC KEY
+/// message
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AddressFormat {
+    /// Legacy P2PKH address
+    Legacy,
+    /// P2SH-wrapped segwit address
+    P2shSegwit,
+    /// Native segwit (bech32) address
+    NativeSegwit
+}
+
+impl AddressFormat {
+    fn to_p2(&self) -> u8 {
+        match *self {
+            AddressFormat::Legacy => 0x00,
+            AddressFormat::P2shSegwit => 0x01,
+            AddressFormat::NativeSegwit => 0x02
+        }
+    }
+}
+
 /// GET WALLET PUBLIC KEY  message
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct GetWalletPublicKey<'a> {
@@ -165,18 +298,30 @@ pub struct GetWalletPublicKey<'a> {
     reply: Vec<u8>,
     sw: u16,
     bip32_path: &'a [u32],
+    display: bool,
+    address_format: AddressFormat
 }
 
 impl<'a> GetWalletPublicKey<'a> {
-    /// Constructor
+    /// Constructor. Equivalent to `new_with_options(bip32_path, false, AddressFormat::Legacy)`.
     pub fn new(bip32_path: &'a [u32]) -> GetWalletPublicKey {
+        GetWalletPublicKey::new_with_options(bip32_path, false, AddressFormat::Legacy)
+    }
+
+    /// Constructor. If `display` is set, the device will show the address on
+    /// its own screen and require the user to confirm it before replying;
+    /// `address_format` selects the encoding the device uses for the returned
+    /// address.
+    pub fn new_with_options(bip32_path: &'a [u32], display: bool, address_format: AddressFormat) -> GetWalletPublicKey {
         assert!(bip32_path.len() < 11);  // limitation of the Nano S
 
         GetWalletPublicKey {
             sent: false,
             reply: vec![],
             sw: 0,
-            bip32_path: bip32_path
+            bip32_path: bip32_path,
+            display: display,
+            address_format: address_format
         }
     }
 }
@@ -191,8 +336,8 @@ impl<'a> Command for GetWalletPublicKey<'a> {
         let mut ret = Vec::with_capacity(5 + 4 * self.bip32_path.len());
         ret.push(apdu::ledger::BTCHIP_CLA);
         ret.push(apdu::ledger::ins::GET_WALLET_PUBLIC_KEY);
-        ret.push(0);
-        ret.push(0);
+        ret.push(if self.display { 0x01 } else { 0x00 });
+        ret.push(self.address_format.to_p2());
         ret.push((1 + 4 * self.bip32_path.len()) as u8);
         ret.push(self.bip32_path.len() as u8);
         for childnum in self.bip32_path {
@@ -202,14 +347,14 @@ impl<'a> Command for GetWalletPublicKey<'a> {
     }
 
     fn decode_reply(&mut self, mut data: Vec<u8>) -> Result<(), Error> {
-        if data.len() < 2 {
-            return Err(Error::UnexpectedEof);
-        }
-        let sw2 = data.pop().unwrap();
-        let sw1 = data.pop().unwrap();
+        self.sw = try!(take_status_word(&mut data));
         self.reply = data;
-        self.sw = ((sw1 as u16) << 8) + sw2 as u16;
-        Ok(())
+        let status = StatusWord::from_u16(self.sw);
+        if status.is_ok() {
+            Ok(())
+        } else {
+            Err(Error::ApduBadStatus(status))
+        }
     }
 
     fn into_reply(self) -> (u16, Vec<u8>) {
@@ -222,8 +367,10 @@ impl<'a> Command for GetWalletPublicKey<'a> {
 pub struct WalletPublicKey {
     /// The EC public key
     pub public_key: PublicKey,
-    /// The base58-encoded address corresponding to the public key
-    pub b58_address: String,
+    /// The address corresponding to the public key, encoded the way it was
+    /// requested on the `GetWalletPublicKey` command (base58check for
+    /// `AddressFormat::Legacy`/`P2shSegwit`, bech32 for `AddressFormat::NativeSegwit`)
+    pub address: String,
     /// The BIP32 chaincode associated to this key
     pub chaincode: [u8; 32]
 }
@@ -246,7 +393,7 @@ impl Response for WalletPublicKey {
 
         let mut ret = WalletPublicKey {
             public_key: pk,
-            b58_address: addr,
+            address: addr,
             chaincode: [0; 32]
         };
         ret.chaincode.clone_from_slice(&data[2 + pk_len + addr_len..]);
@@ -330,20 +477,16 @@ impl<'a> Command for SignMessagePrepare<'a> {
     }
 
     fn decode_reply(&mut self, mut data: Vec<u8>) -> Result<(), Error> {
-        if data.len() < 2 {
-            return Err(Error::UnexpectedEof);
-        }
-        let sw2 = data.pop().unwrap();
-        let sw1 = data.pop().unwrap();
+        self.sw = try!(take_status_word(&mut data));
         if data.len() > 2 {
             return Err(Error::Unsupported);
         }
         self.reply = data;
-        self.sw = ((sw1 as u16) << 8) + sw2 as u16;
-        if self.sw != apdu::ledger::sw::OK {
-            Err(Error::ApduBadStatus(self.sw))
-        } else {
+        let status = StatusWord::from_u16(self.sw);
+        if status.is_ok() {
             Ok(())
+        } else {
+            Err(Error::ApduBadStatus(status))
         }
     }
 
@@ -389,14 +532,14 @@ impl Command for SignMessageSign {
     }
 
     fn decode_reply(&mut self, mut data: Vec<u8>) -> Result<(), Error> {
-        if data.len() < 2 {
-            return Err(Error::UnexpectedEof);
-        }
-        let sw2 = data.pop().unwrap();
-        let sw1 = data.pop().unwrap();
+        self.sw = try!(take_status_word(&mut data));
         self.reply = data;
-        self.sw = ((sw1 as u16) << 8) + sw2 as u16;
-        Ok(())
+        let status = StatusWord::from_u16(self.sw);
+        if status.is_ok() {
+            Ok(())
+        } else {
+            Err(Error::ApduBadStatus(status))
+        }
     }
 
     fn into_reply(self) -> (u16, Vec<u8>) {
@@ -404,6 +547,104 @@ impl Command for SignMessageSign {
     }
 }
 
+/// Response to the SIGN MESSAGE sign message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageSignature {
+    /// The recovery id / public key parity bit returned alongside the signature
+    pub recovery_id: u8,
+    /// The `r` component of the ECDSA signature
+    pub r: [u8; 32],
+    /// The `s` component of the ECDSA signature
+    pub s: [u8; 32]
+}
+
+impl MessageSignature {
+    /// Serializes this signature into the compact 65-byte `[v, r, s]` form,
+    /// with `v` set to `27 + recovery_id`.
+    pub fn to_compact(&self) -> [u8; 65] {
+        let mut ret = [0; 65];
+        ret[0] = 27 + self.recovery_id;
+        ret[1..33].copy_from_slice(&self.r);
+        ret[33..65].copy_from_slice(&self.s);
+        ret
+    }
+}
+
+/// Copies a DER-encoded unsigned integer into a fixed-size big-endian buffer,
+/// stripping the leading zero byte DER adds to keep the high bit from
+/// being mistaken for a sign bit.
+fn copy_der_integer(dst: &mut [u8; 32], src: &[u8]) -> Result<(), Error> {
+    let src = if src.len() > 1 && src[0] == 0x00 { &src[1..] } else { src };
+    if src.is_empty() || src.len() > 32 {
+        return Err(Error::ResponseWrongLength(apdu::ledger::ins::SIGN_MESSAGE, src.len()));
+    }
+    let start = 32 - src.len();
+    dst[start..].copy_from_slice(src);
+    Ok(())
+}
+
+impl Response for MessageSignature {
+    fn decode(data: &[u8]) -> Result<MessageSignature, Error> {
+        // First byte: recovery id / public key parity; remainder: DER signature
+        if data.len() < 1 + 8 {
+            return Err(Error::UnexpectedEof);
+        }
+        let recovery_id = data[0];
+        if recovery_id > 3 {
+            return Err(Error::InvalidRecoveryId(recovery_id));
+        }
+        let der = &data[1..];
+
+        if der.len() < 6 || der[0] != 0x30 {
+            return Err(Error::ResponseWrongLength(apdu::ledger::ins::SIGN_MESSAGE, data.len()));
+        }
+        if der[1] as usize > der.len() - 2 {
+            return Err(Error::UnexpectedEof);
+        }
+        if der[1] as usize != der.len() - 2 {
+            return Err(Error::ResponseWrongLength(apdu::ledger::ins::SIGN_MESSAGE, data.len()));
+        }
+
+        let mut idx = 2;
+        if der[idx] != 0x02 {
+            return Err(Error::ResponseWrongLength(apdu::ledger::ins::SIGN_MESSAGE, data.len()));
+        }
+        idx += 1;
+        let r_len = der[idx] as usize;
+        idx += 1;
+        if idx + r_len > der.len() {
+            return Err(Error::UnexpectedEof);
+        }
+        let r_bytes = &der[idx..idx + r_len];
+        idx += r_len;
+
+        if idx >= der.len() || der[idx] != 0x02 {
+            return Err(Error::ResponseWrongLength(apdu::ledger::ins::SIGN_MESSAGE, data.len()));
+        }
+        idx += 1;
+        if idx >= der.len() {
+            return Err(Error::UnexpectedEof);
+        }
+        let s_len = der[idx] as usize;
+        idx += 1;
+        if idx + s_len != der.len() {
+            return Err(Error::ResponseWrongLength(apdu::ledger::ins::SIGN_MESSAGE, data.len()));
+        }
+        let s_bytes = &der[idx..idx + s_len];
+
+        let mut r = [0; 32];
+        let mut s = [0; 32];
+        try!(copy_der_integer(&mut r, r_bytes));
+        try!(copy_der_integer(&mut s, s_bytes));
+
+        Ok(MessageSignature {
+            recovery_id: recovery_id,
+            r: r,
+            s: s
+        })
+    }
+}
+
 /// GET RANDOM message
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct GetRandom {
@@ -440,14 +681,14 @@ impl Command for GetRandom {
     }
 
     fn decode_reply(&mut self, mut data: Vec<u8>) -> Result<(), Error> {
-        if data.len() < 2 {
-            return Err(Error::UnexpectedEof);
-        }
-        let sw2 = data.pop().unwrap();
-        let sw1 = data.pop().unwrap();
+        self.sw = try!(take_status_word(&mut data));
         self.reply = data;
-        self.sw = ((sw1 as u16) << 8) + sw2 as u16;
-        Ok(())
+        let status = StatusWord::from_u16(self.sw);
+        if status.is_ok() {
+            Ok(())
+        } else {
+            Err(Error::ApduBadStatus(status))
+        }
     }
 
     fn into_reply(self) -> (u16, Vec<u8>) {
@@ -527,14 +768,370 @@ impl Command for GetTrustedInput {
 
     fn decode_reply(&mut self, mut data: Vec<u8>) -> Result<(), Error> {
         // Note that only the last reply is nonempty for this one
-        if data.len() < 2 {
-            return Err(Error::UnexpectedEof);
+        self.sw = try!(take_status_word(&mut data));
+        self.reply = data;
+        let status = StatusWord::from_u16(self.sw);
+        if status.is_ok() {
+            Ok(())
+        } else {
+            Err(Error::ApduBadStatus(status))
+        }
+    }
+
+    fn into_reply(self) -> (u16, Vec<u8>) {
+        (self.sw, self.reply)
+    }
+}
+
+/// Response to the GET TRUSTED INPUT message
+///
+/// The blob is HMAC-protected by the device and must be replayed back to it
+/// verbatim in later signing commands; we have no way to check the MAC
+/// ourselves, so it is kept opaque rather than re-serialized from its parts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrustedInput {
+    /// The raw blob, exactly as returned by the device
+    blob: Vec<u8>,
+    /// The hash of the transaction whose output this trusted input refers to
+    pub prevout_hash: [u8; 32],
+    /// The index of the output within that transaction
+    pub vout: u32,
+    /// The amount, in satoshis, of that output
+    pub amount: u64
+}
+
+impl TrustedInput {
+    /// The raw blob that must be passed back to the device, unmodified, when
+    /// referencing this trusted input in a later signing command
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.blob
+    }
+}
+
+impl Response for TrustedInput {
+    fn decode(data: &[u8]) -> Result<TrustedInput, Error> {
+        // 2-byte magic, 8-byte nonce, 32-byte prevout hash, 4-byte vout,
+        // 8-byte amount, 8-byte HMAC
+        if data.len() != 62 {
+            return Err(Error::ResponseWrongLength(apdu::ledger::ins::GET_TRUSTED_INPUT, data.len()));
+        }
+
+        let mut prevout_hash = [0; 32];
+        prevout_hash.clone_from_slice(&data[10..42]);
+
+        Ok(TrustedInput {
+            blob: data.to_owned(),
+            prevout_hash: prevout_hash,
+            vout: try!((&data[42..46]).read_u32::<LittleEndian>().map_err(|_| Error::UnexpectedEof)),
+            amount: try!((&data[46..54]).read_u64::<LittleEndian>().map_err(|_| Error::UnexpectedEof))
+        })
+    }
+}
+
+/// UNTRUSTED HASH TRANSACTION INPUT START message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UntrustedHashTransactionInputStart {
+    sent_cuts: usize,
+    reply: Vec<u8>,
+    sw: u16,
+    ser_data: Vec<u8>,
+    cuts: Vec<usize>,
+    new_transaction: bool
+}
+
+impl UntrustedHashTransactionInputStart {
+    /// Constructor. `trusted_inputs` must have one entry per input of `tx`, in
+    /// order; `signing_index` selects which input's `subscript` (its scriptSig
+    /// or redeem script) is actually sent to the device, since the protocol
+    /// requires every other input's script to be sent empty. The length field
+    /// in front of the subscript is sized according to `firmware_version`, per
+    /// `FirmwareVersion::trusted_input_header_len`; firmware older than
+    /// `DEPRECATE_VERSION_BEFORE` is rejected with `Error::FirmwareTooOld`,
+    /// since this crate doesn't know how to talk to it. `apdu_size` bounds
+    /// every APDU this command will ever produce, exactly as for
+    /// `GetTrustedInput::new`; `subscript` is chunked against it so that a
+    /// large redeem script can never leave a single cut too big to fit in one
+    /// APDU.
+    pub fn new(
+        tx: &Transaction,
+        trusted_inputs: &[TrustedInput],
+        signing_index: usize,
+        subscript: &[u8],
+        new_transaction: bool,
+        firmware_version: &FirmwareVersion,
+        apdu_size: usize
+    ) -> Result<UntrustedHashTransactionInputStart, Error> {
+        try!(firmware_version.check_not_deprecated());
+
+        assert_eq!(tx.input.len(), trusted_inputs.len());
+        assert!(signing_index < tx.input.len());
+        assert!(apdu_size > 16);  // sanity: must fit more than just the APDU header
+
+        let wide_len_field = firmware_version.trusted_input_header_len() > 7;
+        let max_script_len = if wide_len_field { 0x10000 } else { 0x100 };
+        assert!(subscript.len() < max_script_len);  // limitation of the length-prefix field width
+
+        // No single cut is ever allowed to be this large, leaving room for
+        // the 5-byte APDU header plus a one-byte safety margin.
+        let max_chunk_len = apdu_size - 6;
+        // The largest cut that is not split is an input record's header: the
+        // marker, the blob length prefix, the 62-byte blob and the subscript
+        // length prefix.
+        assert!(max_chunk_len >= 1 + 1 + 62 + if wide_len_field { 2 } else { 1 });
+
+        let mut ser_data = vec![];
+        let mut cuts = vec![0];
+
+        let _ = ser_data.write_u32::<LittleEndian>(tx.version as u32);
+        ser_data.push(tx.input.len() as u8);  // limitation: no varint, as elsewhere in this file
+
+        for (n, (input, trusted_input)) in tx.input.iter().zip(trusted_inputs).enumerate() {
+            cuts.push(ser_data.len());
+            ser_data.push(0x01);  // marker: input is given as a trusted input
+            let blob = trusted_input.as_bytes();
+            // The trusted-input blob is always a fixed 62 bytes (see
+            // `TrustedInput::decode`), so its length prefix is always a
+            // single byte regardless of firmware; only the caller-controlled
+            // subscript below needs the wider, firmware-dependent field.
+            ser_data.push(blob.len() as u8);
+            ser_data.extend(blob);
+
+            let script = if n == signing_index { subscript } else { &[][..] };
+            if wide_len_field {
+                let _ = ser_data.write_u16::<BigEndian>(script.len() as u16);
+            } else {
+                ser_data.push(script.len() as u8);
+            }
+
+            // The script is the one field that's caller-controlled and can be
+            // large (e.g. a multisig redeem script), so it gets its own dense
+            // cutpoints rather than riding along with the rest of the record.
+            let mut script_off = 0;
+            while script_off < script.len() {
+                cuts.push(ser_data.len() + script_off);
+                script_off = cmp::min(script_off + max_chunk_len, script.len());
+            }
+            ser_data.extend(script);
+
+            cuts.push(ser_data.len());
+            let _ = ser_data.write_u32::<LittleEndian>(input.sequence);
+        }
+        cuts.push(ser_data.len());
+
+        Ok(UntrustedHashTransactionInputStart {
+            sent_cuts: 0,
+            reply: vec![],
+            sw: 0,
+            ser_data: ser_data,
+            cuts: cuts,
+            new_transaction: new_transaction
+        })
+    }
+}
+
+impl Command for UntrustedHashTransactionInputStart {
+    fn encode_next(&mut self, apdu_size: usize) -> Option<Vec<u8>> {
+        if self.sent_cuts >= self.cuts.len() {
+            unreachable!();  // sanity check
+        }
+        // We are always looking one cut ahead (and have an extra
+        // "cut" at self.ser_data.len() for this reason).
+        if self.sent_cuts == self.cuts.len() - 1 {
+            return None;
+        }
+
+        let mut ret = Vec::with_capacity(apdu_size);
+        ret.push(apdu::ledger::BTCHIP_CLA);
+        ret.push(apdu::ledger::ins::UNTRUSTED_HASH_TRANSACTION_INPUT_START);
+        ret.push(if self.sent_cuts == 0 { 0x00 } else { 0x80 });
+        ret.push(if self.new_transaction { 0x00 } else { 0x80 });
+        ret.push(0x00);  // Will overwrite this with final length
+
+        let mut next_cut_len = self.cuts[self.sent_cuts + 1] - self.cuts[self.sent_cuts];
+        while ret.len() + next_cut_len < apdu_size {
+            ret.extend(&self.ser_data[self.cuts[self.sent_cuts]..self.cuts[self.sent_cuts + 1]]);
+            self.sent_cuts += 1;
+            if self.sent_cuts < self.cuts.len() - 1 {
+                next_cut_len = self.cuts[self.sent_cuts + 1] - self.cuts[self.sent_cuts];
+            } else {
+                break;
+            }
+        }
+
+        assert!(ret.len() < apdu_size);
+        ret[4] = (ret.len() - 5) as u8;
+        Some(ret)
+    }
+
+    fn decode_reply(&mut self, mut data: Vec<u8>) -> Result<(), Error> {
+        self.sw = try!(take_status_word(&mut data));
+        self.reply = data;
+        let status = StatusWord::from_u16(self.sw);
+        if status.is_ok() {
+            Ok(())
+        } else {
+            Err(Error::ApduBadStatus(status))
+        }
+    }
+
+    fn into_reply(self) -> (u16, Vec<u8>) {
+        (self.sw, self.reply)
+    }
+}
+
+/// UNTRUSTED HASH TRANSACTION INPUT FINALIZE FULL message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UntrustedHashTransactionInputFinalizeFull {
+    sent_cuts: usize,
+    reply: Vec<u8>,
+    sw: u16,
+    ser_data: Vec<u8>,
+    cuts: Vec<usize>
+}
+
+impl UntrustedHashTransactionInputFinalizeFull {
+    /// Constructor. Streams every output of `tx`, in order; this happens once
+    /// per signing session regardless of how many inputs are being signed.
+    pub fn new(tx: &Transaction) -> UntrustedHashTransactionInputFinalizeFull {
+        let mut ser_data = vec![];
+        let mut cuts = vec![0];
+
+        ser_data.push(tx.output.len() as u8);  // limitation: no varint, as elsewhere in this file
+        for output in &tx.output {
+            cuts.push(ser_data.len());
+            let _ = ser_data.write_u64::<LittleEndian>(output.value);
+            let script = &output.script_pubkey[..];
+            ser_data.push(script.len() as u8);
+            ser_data.extend(script);
+        }
+        cuts.push(ser_data.len());
+
+        UntrustedHashTransactionInputFinalizeFull {
+            sent_cuts: 0,
+            reply: vec![],
+            sw: 0,
+            ser_data: ser_data,
+            cuts: cuts
+        }
+    }
+
+    /// Whether the device is asking the user to validate the output data
+    /// on-screen before it will sign. Only meaningful once the command has
+    /// run to completion (i.e. `encode_next` has returned `None`).
+    pub fn user_confirmation_required(&self) -> bool {
+        self.reply.get(0).map_or(false, |&b| b != 0)
+    }
+}
+
+impl Command for UntrustedHashTransactionInputFinalizeFull {
+    fn encode_next(&mut self, apdu_size: usize) -> Option<Vec<u8>> {
+        if self.sent_cuts >= self.cuts.len() {
+            unreachable!();  // sanity check
+        }
+        if self.sent_cuts == self.cuts.len() - 1 {
+            return None;
+        }
+
+        let mut ret = Vec::with_capacity(apdu_size);
+        ret.push(apdu::ledger::BTCHIP_CLA);
+        ret.push(apdu::ledger::ins::UNTRUSTED_HASH_TRANSACTION_INPUT_FINALIZE_FULL);
+        ret.push(if self.sent_cuts == 0 { 0x00 } else { 0x80 });
+        ret.push(0x00);
+        ret.push(0x00);  // Will overwrite this with final length
+
+        let mut next_cut_len = self.cuts[self.sent_cuts + 1] - self.cuts[self.sent_cuts];
+        while ret.len() + next_cut_len < apdu_size {
+            ret.extend(&self.ser_data[self.cuts[self.sent_cuts]..self.cuts[self.sent_cuts + 1]]);
+            self.sent_cuts += 1;
+            if self.sent_cuts < self.cuts.len() - 1 {
+                next_cut_len = self.cuts[self.sent_cuts + 1] - self.cuts[self.sent_cuts];
+            } else {
+                break;
+            }
+        }
+
+        assert!(ret.len() < apdu_size);
+        ret[4] = (ret.len() - 5) as u8;
+        Some(ret)
+    }
+
+    fn decode_reply(&mut self, mut data: Vec<u8>) -> Result<(), Error> {
+        // Note that only the last reply is nonempty for this one
+        self.sw = try!(take_status_word(&mut data));
+        self.reply = data;
+        let status = StatusWord::from_u16(self.sw);
+        if status.is_ok() {
+            Ok(())
+        } else {
+            Err(Error::ApduBadStatus(status))
+        }
+    }
+
+    fn into_reply(self) -> (u16, Vec<u8>) {
+        (self.sw, self.reply)
+    }
+}
+
+/// UNTRUSTED HASH SIGN message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UntrustedHashSign<'a> {
+    sent: bool,
+    reply: Vec<u8>,
+    sw: u16,
+    bip32_path: &'a [u32],
+    locktime: u32,
+    sighash_type: u8
+}
+
+impl<'a> UntrustedHashSign<'a> {
+    /// Constructor
+    pub fn new(bip32_path: &'a [u32], locktime: u32, sighash_type: u8) -> UntrustedHashSign<'a> {
+        assert!(bip32_path.len() < 11);  // limitation of the Nano S
+
+        UntrustedHashSign {
+            sent: false,
+            reply: vec![],
+            sw: 0,
+            bip32_path: bip32_path,
+            locktime: locktime,
+            sighash_type: sighash_type
+        }
+    }
+}
+
+impl<'a> Command for UntrustedHashSign<'a> {
+    fn encode_next(&mut self, _apdu_size: usize) -> Option<Vec<u8>> {
+        if self.sent {
+            return None;
         }
-        let sw2 = data.pop().unwrap();
-        let sw1 = data.pop().unwrap();
+        self.sent = true;
+
+        let len = 1 + 4 * self.bip32_path.len() + 1 + 4 + 1;
+        let mut ret = Vec::with_capacity(5 + len);
+        ret.push(apdu::ledger::BTCHIP_CLA);
+        ret.push(apdu::ledger::ins::UNTRUSTED_HASH_SIGN);
+        ret.push(0x00);
+        ret.push(0x00);
+        ret.push(len as u8);
+        ret.push(self.bip32_path.len() as u8);
+        for childnum in self.bip32_path {
+            let _ = ret.write_u32::<BigEndian>(*childnum);
+        }
+        ret.push(0x00);  // deprecated user PIN field, always empty
+        let _ = ret.write_u32::<LittleEndian>(self.locktime);
+        ret.push(self.sighash_type);
+        Some(ret)
+    }
+
+    fn decode_reply(&mut self, mut data: Vec<u8>) -> Result<(), Error> {
+        self.sw = try!(take_status_word(&mut data));
         self.reply = data;
-        self.sw = ((sw1 as u16) << 8) + sw2 as u16;
-        Ok(())
+        let status = StatusWord::from_u16(self.sw);
+        if status.is_ok() {
+            Ok(())
+        } else {
+            Err(Error::ApduBadStatus(status))
+        }
     }
 
     fn into_reply(self) -> (u16, Vec<u8>) {
@@ -542,10 +1139,103 @@ impl Command for GetTrustedInput {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusted_input_blob() -> Vec<u8> {
+        let mut blob = vec![0x32, 0x00]; // 2-byte magic
+        blob.extend([0xaa; 8].iter().cloned()); // 8-byte nonce
+        blob.extend([0x11; 32].iter().cloned()); // prevout hash
+        blob.extend([0x02, 0x00, 0x00, 0x00].iter().cloned()); // vout = 2
+        blob.extend([0x64, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00].iter().cloned()); // amount = 100
+        blob.extend([0xbb; 8].iter().cloned()); // 8-byte HMAC
+        assert_eq!(blob.len(), 62);
+        blob
+    }
+
+    #[test]
+    fn trusted_input_decode_roundtrip() {
+        let blob = trusted_input_blob();
+        let ti = TrustedInput::decode(&blob).unwrap();
+        assert_eq!(ti.prevout_hash, [0x11; 32]);
+        assert_eq!(ti.vout, 2);
+        assert_eq!(ti.amount, 100);
+        assert_eq!(ti.as_bytes(), &blob[..]);
+    }
 
+    #[test]
+    fn trusted_input_decode_wrong_length() {
+        let mut blob = trusted_input_blob();
+        blob.pop();
+        match TrustedInput::decode(&blob) {
+            Err(Error::ResponseWrongLength(apdu::ledger::ins::GET_TRUSTED_INPUT, 61)) => {},
+            other => panic!("expected ResponseWrongLength, got {:?}", other)
+        }
+    }
 
+    fn der_signature(r: &[u8], s: &[u8]) -> Vec<u8> {
+        let mut der = vec![0x30, (r.len() + s.len() + 4) as u8];
+        der.push(0x02);
+        der.push(r.len() as u8);
+        der.extend(r.iter().cloned());
+        der.push(0x02);
+        der.push(s.len() as u8);
+        der.extend(s.iter().cloned());
+        der
+    }
 
+    fn message_signature_reply(recovery_id: u8, r: &[u8], s: &[u8]) -> Vec<u8> {
+        let mut data = vec![recovery_id];
+        data.extend(der_signature(r, s));
+        data
+    }
 
+    #[test]
+    fn message_signature_decode_roundtrip() {
+        let r = [0x42; 32];
+        let s = [0x24; 32];
+        let data = message_signature_reply(1, &r, &s);
+        let sig = MessageSignature::decode(&data).unwrap();
+        assert_eq!(sig.recovery_id, 1);
+        assert_eq!(sig.r, r);
+        assert_eq!(sig.s, s);
+    }
+
+    #[test]
+    fn message_signature_decode_strips_der_leading_zero() {
+        let mut r_der = vec![0x00];
+        r_der.extend([0xff; 31].iter().cloned());
+        let s = [0x01; 32];
+        let data = message_signature_reply(0, &r_der, &s);
+        let sig = MessageSignature::decode(&data).unwrap();
+        let mut expected_r = [0xff; 32];
+        expected_r[0] = 0x00;
+        assert_eq!(sig.r, expected_r);
+        assert_eq!(sig.s, s);
+    }
+
+    #[test]
+    fn message_signature_decode_rejects_bad_recovery_id() {
+        let data = message_signature_reply(4, &[0x01; 32], &[0x02; 32]);
+        match MessageSignature::decode(&data) {
+            Err(Error::InvalidRecoveryId(4)) => {},
+            other => panic!("expected InvalidRecoveryId, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn message_signature_decode_rejects_truncated_der() {
+        let mut data = message_signature_reply(0, &[0x01; 32], &[0x02; 32]);
+        // Truncate right after the s-tag byte, before its length byte.
+        let s_tag_idx = data.len() - 34;
+        data.truncate(s_tag_idx + 1);
+        match MessageSignature::decode(&data) {
+            Err(Error::UnexpectedEof) => {},
+            other => panic!("expected UnexpectedEof, got {:?}", other)
+        }
+    }
+}
 
 
 
@@ -0,0 +1,56 @@
+// IceBox
+// Written in 2017 by
+//   Andrew Poelstra <icebox@wpsoftware.net>
+//
+// To the extent possible under law, the author(s) have dedicated all
+// copyright and related and neighboring rights to this software to
+// the public domain worldwide. This software is distributed without
+// any warranty.
+//
+// You should have received a copy of the CC0 Public Domain Dedication
+// along with this software.
+// If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//
+
+//! # Errors
+//!
+//! The catch-all error type used throughout the crate
+//!
+
+use std::string;
+use secp256k1;
+
+use dongle::message::StatusWord;
+
+/// Catch-all error type for this crate
+#[derive(Debug)]
+pub enum Error {
+    /// Ran out of data while parsing a reply
+    UnexpectedEof,
+    /// A reply was the wrong length for the instruction that produced it
+    ResponseWrongLength(u8, usize),
+    /// The command is not supported in the way the device replied to it
+    Unsupported,
+    /// The device replied with a non-OK status word
+    ApduBadStatus(StatusWord),
+    /// The device firmware is older than the minimum this crate supports
+    FirmwareTooOld,
+    /// A signature reply's recovery id was outside the valid 0-3 range
+    InvalidRecoveryId(u8),
+    /// An error from the `secp256k1` library
+    Secp256k1(secp256k1::Error),
+    /// A string returned by the device was not valid UTF8
+    Utf8(string::FromUtf8Error)
+}
+
+impl From<secp256k1::Error> for Error {
+    fn from(e: secp256k1::Error) -> Error {
+        Error::Secp256k1(e)
+    }
+}
+
+impl From<string::FromUtf8Error> for Error {
+    fn from(e: string::FromUtf8Error) -> Error {
+        Error::Utf8(e)
+    }
+}